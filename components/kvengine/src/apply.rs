@@ -35,34 +35,164 @@ impl Engine {
                 shard.id, shard.ver, seq, cs.sequence
             );
             return Ok(());
-        } else {
-            store_u64(&shard.meta_seq, cs.sequence);
         }
+        // Fsync the edit into the manifest *before* any in-memory CAS and before
+        // the apply methods route deleted ids onto the GC queue (which is itself
+        // durable). Otherwise a crash after the GC enqueue but before the edit is
+        // recorded would leave the delete queue scheduled to remove files the
+        // replayed manifest still lists as live, and the worker would delete them
+        // after the grace period. `meta_seq` is advanced only once the edit is
+        // durable: if the fsync in `append` fails we return `Err` with memory and
+        // `meta_seq` untouched, so the retry is not suppressed as a duplicate.
+        self.manifest.append(&cs)?;
+        store_u64(&shard.meta_seq, cs.sequence);
         if cs.has_flush() {
-            self.apply_flush(shard, g, cs)?
+            self.apply_flush(shard, g, &cs)?
         } else if cs.has_compaction() {
-            let resut = self.apply_compaction(shard, g, cs);
+            let resut = self.apply_compaction(shard, g, &cs);
             store_bool(&shard.compacting, false);
-            if resut.is_err() {
-                return resut;
-            }
+            resut?;
         } else if cs.has_split_files() {
-            self.apply_split_files(shard, g, cs)?
+            self.apply_split_files(shard, g, &cs)?
+        } else if cs.has_snapshot() {
+            self.apply_snapshot(shard, g, &cs)?
         }
+        self.update_shard_merkle(shard, g);
         shard.refresh_estimated_size();
         Ok(())
     }
 
+    /// Recomputes the shard's Merkle fingerprint from its current live file set
+    /// and stores the root on the shard. The manifest does not store this root;
+    /// it derives the same root from its recorded live file set
+    /// (`ShardManifest::merkle_root`), so roots can be compared after restart.
+    fn update_shard_merkle<'a>(&self, shard: &'a Shard, g: &'a epoch::Guard) {
+        let mut entries = vec![];
+        for l0 in &shard.get_l0_tbls(g).tbls {
+            entries.push(merkle::FileEntry {
+                cf: -1,
+                level: 0,
+                file_id: l0.id(),
+                smallest: l0.smallest().to_vec(),
+                biggest: l0.biggest().to_vec(),
+            });
+        }
+        for cf in 0..NUM_CFS {
+            let scf = shard.get_cf(cf, g);
+            for (idx, level) in scf.levels.iter().enumerate() {
+                for tbl in &level.tables {
+                    entries.push(merkle::FileEntry {
+                        cf: cf as i32,
+                        level: idx as u32 + 1,
+                        file_id: tbl.id(),
+                        smallest: tbl.smallest().to_vec(),
+                        biggest: tbl.biggest().to_vec(),
+                    });
+                }
+            }
+        }
+        let tree = merkle::MerkleTree::build(entries);
+        shard.set_merkle_root(tree.root());
+    }
+
+    fn apply_snapshot<'a>(
+        &self,
+        shard: &'a Shard,
+        g: &'a epoch::Guard,
+        cs: &pb::ChangeSet,
+    ) -> Result<()> {
+        let snap = cs.get_snapshot();
+        let fs_opts = dfs::Options::new(shard.id, shard.ver);
+        // Open and build every new resource *before* the first `cas_resource`, so
+        // a failure to open a file leaves the shard's old L0s/CFs untouched: the
+        // CAS swaps are infallible, so the rebuild is all-or-nothing. The
+        // all-or-nothing property comes solely from staging every open up front,
+        // not from any `meta_seq` ordering.
+        let mut new_l0s = L0Tables::new(vec![]);
+        for l0 in snap.get_l0_creates() {
+            let file = self.open_table_file(l0.id, fs_opts)?;
+            let l0_tbl = sstable::L0Table::new(file, self.cache.clone())?;
+            new_l0s.tbls.push(l0_tbl);
+        }
+        new_l0s
+            .tbls
+            .sort_by(|a, b| b.commit_ts().cmp(&a.commit_ts()));
+        let mut new_cfs: Vec<ShardCF> = Vec::new();
+        for cf in 0..NUM_CFS {
+            let max_level = self.opts.cfs[cf].max_levels;
+            new_cfs.push(ShardCF::new(max_level));
+        }
+        for tbl in snap.get_table_creates() {
+            // A snapshot `TableCreate` must name an LN level (>= 1); level 0 is
+            // reserved for L0 tables, and `tbl.level as usize - 1` would underflow.
+            if tbl.level < 1 {
+                return Err(Error::InvalidFileLevel {
+                    id: tbl.id,
+                    level: tbl.level,
+                });
+            }
+            let cf = tbl.cf as usize;
+            let scf = &mut new_cfs[cf];
+            let new_handler = &mut scf.levels[tbl.level as usize - 1];
+            let file = self.open_table_file(tbl.id, fs_opts)?;
+            new_handler.total_size += file.size();
+            let table = sstable::SSTable::new(file, self.cache.clone())?;
+            new_handler.tables.push(table);
+        }
+        for new_cf in &mut new_cfs {
+            for new_handler in &mut new_cf.levels {
+                new_handler
+                    .tables
+                    .sort_by(|a, b| a.smallest().cmp(b.smallest()));
+                assert_tables_order(&new_handler.tables);
+            }
+        }
+        // Everything opened cleanly; from here on only infallible CAS swaps run.
+        // Collect every file id the shard currently references so we can drop the
+        // ones the snapshot no longer keeps once the new resources are installed.
+        let mut old_files = HashSet::new();
+        let (old_l0s_shared, old_l0s) = load_resource_with_shared(&shard.l0_tbls, g);
+        for old_l0 in &old_l0s.tbls {
+            old_files.insert(old_l0.id());
+        }
+        let ok = cas_resource(&shard.l0_tbls, g, old_l0s_shared, new_l0s);
+        assert!(ok);
+        new_cfs.reverse();
+        for cf in 0..NUM_CFS {
+            let new_cf = new_cfs.pop().unwrap();
+            let (old_shared, old_cf) = load_resource_with_shared(&shard.cfs[cf], g);
+            for old_handler in &old_cf.levels {
+                for old_tbl in &old_handler.tables {
+                    old_files.insert(old_tbl.id());
+                }
+            }
+            let ok = cas_resource(&shard.cfs[cf], g, old_shared, new_cf);
+            assert!(ok);
+        }
+        // Anything the shard held before that the snapshot does not re-create is
+        // now orphaned in DFS and can be scheduled for removal.
+        for l0 in snap.get_l0_creates() {
+            old_files.remove(&l0.id);
+        }
+        for tbl in snap.get_table_creates() {
+            old_files.remove(&tbl.id);
+        }
+        store_bool(&shard.initial_flushed, true);
+        shard.set_split_stage(cs.get_stage());
+        self.remove_dfs_files(shard, g, old_files);
+        Ok(())
+    }
+
     pub fn apply_flush<'a>(
         &self,
         shard: &'a Shard,
         g: &'a epoch::Guard,
-        cs: pb::ChangeSet,
+        cs: &pb::ChangeSet,
     ) -> Result<()> {
         let flush = cs.get_flush();
         if flush.has_l0_create() {
             let opts = dfs::Options::new(shard.id, shard.ver);
-            let file = self.fs.open(flush.get_l0_create().id, opts)?;
+            let file = self.open_table_file(flush.get_l0_create().id, opts)?;
             let l0_tbl = sstable::L0Table::new(file, self.cache.clone())?;
             shard.atomic_add_l0_table(g, l0_tbl);
             shard.atomic_remove_mem_table(g);
@@ -76,12 +206,12 @@ impl Engine {
         &self,
         shard: &'a Shard,
         g: &'a epoch::Guard,
-        mut cs: pb::ChangeSet,
+        cs: &pb::ChangeSet,
     ) -> Result<()> {
-        let comp = cs.take_compaction();
+        let comp = cs.get_compaction();
         let mut del_files = HashSet::new();
         if comp.conflicted {
-            if is_move_down(&comp) {
+            if is_move_down(comp) {
                 return Ok(());
             }
             for create in comp.get_table_creates() {
@@ -139,14 +269,16 @@ impl Engine {
         Ok(())
     }
 
-    fn remove_dfs_files<'a>(&self, shard: &'a Shard, g: &'a epoch::Guard, del_files: HashSet<u64>) {
-        let fs = self.fs.clone();
-        let opts = dfs::Options::new(shard.id, shard.ver);
-        g.defer(move || {
-            for id in del_files {
-                fs.remove(id, opts)
+    fn remove_dfs_files<'a>(&self, shard: &'a Shard, _g: &'a epoch::Guard, del_files: HashSet<u64>) {
+        // Don't delete immediately on epoch advance; that races with snapshot
+        // iterators still holding the old resources. Enqueue the ids onto the
+        // crash-safe GC queue, which removes them only after the grace period
+        // elapses and no live reader references them.
+        for id in del_files {
+            if let Err(e) = self.gc.enqueue(id, shard.id, shard.ver) {
+                error!("failed to enqueue dfs file {} for gc: {:?}", id, e);
             }
-        });
+        }
     }
 
     fn compaction_update_level_handler<'a>(
@@ -173,7 +305,7 @@ impl Engine {
             if create.cf as usize != cf {
                 continue;
             }
-            let file = self.fs.open(create.id, opts)?;
+            let file = self.open_table_file(create.id, opts)?;
             let tbl = sstable::SSTable::new(file, self.cache.clone())?;
             new_level.total_size += tbl.size();
             new_level.tables.push(tbl);
@@ -208,7 +340,7 @@ impl Engine {
         &self,
         shard: &'a Shard,
         g: &'a epoch::Guard,
-        cs: pb::ChangeSet,
+        cs: &pb::ChangeSet,
     ) -> Result<()> {
         if shard.get_split_stage() != pb::SplitStage::PreSplitFlushDone {
             error!(
@@ -218,17 +350,21 @@ impl Engine {
             return Err(Error::WrongSplitStage);
         }
         let split_files = cs.get_split_files();
+        // Deleted files are routed through the GC queue, not removed inline, so a
+        // split cannot delete a file out from under a snapshot iterator that still
+        // holds the old resources.
+        let mut del_files = HashSet::new();
         let (old_l0s_shared, old_l0s) = load_resource_with_shared(&shard.l0_tbls, g);
         let mut new_l0s = L0Tables::new(vec![]);
         let fs_opts = dfs::Options::new(shard.id, shard.ver);
         for l0 in split_files.get_l0_creates() {
-            let file = self.fs.open(l0.id, fs_opts)?;
+            let file = self.open_table_file(l0.id, fs_opts)?;
             let l0 = sstable::L0Table::new(file, self.cache.clone())?;
             new_l0s.tbls.push(l0);
         }
         for old_l0 in &old_l0s.tbls {
             if split_files.table_deletes.contains(&old_l0.id()) {
-                self.fs.remove(old_l0.id(), fs_opts);
+                del_files.insert(old_l0.id());
             } else {
                 new_l0s.tbls.push(old_l0.clone());
             }
@@ -249,7 +385,7 @@ impl Engine {
             let scf = &mut new_cfs[cf];
             let level = tbl.level as usize;
             let mut new_handler = &mut scf.levels[level - 1];
-            let file = self.fs.open(tbl.id, fs_opts)?;
+            let file = self.open_table_file(tbl.id, fs_opts)?;
             new_handler.total_size += file.size();
             let table = sstable::SSTable::new(file, self.cache.clone())?;
             new_handler.tables.push(table);
@@ -265,7 +401,7 @@ impl Engine {
                 let new_handler = &mut new_cf.levels[level - 1];
                 for old_tbl in &old_handler.tables {
                     if split_files.table_deletes.contains(&old_tbl.id()) {
-                        self.fs.remove(old_tbl.id(), fs_opts);
+                        del_files.insert(old_tbl.id());
                     } else {
                         new_handler.total_size += old_tbl.size();
                         new_handler.tables.push(old_tbl.clone());
@@ -278,9 +414,21 @@ impl Engine {
             cas_resource(&shard.cfs[cf], g, old_shared, new_cf);
         }
         shard.set_split_stage(cs.get_stage());
+        self.remove_dfs_files(shard, g, del_files);
         Ok(())
     }
 
+    /// Opens a table file from DFS, verifying its CRC32C checksum first when
+    /// `opts.verify_checksum` is enabled so a corrupted block is rejected before
+    /// it is handed to `SSTable::new` / `L0Table::new`.
+    fn open_table_file(&self, id: u64, opts: dfs::Options) -> Result<Arc<dfs::File>> {
+        let file = self.fs.open(id, opts)?;
+        if opts.verify_checksum {
+            checksum::verify_file(id, &file)?;
+        }
+        Ok(file)
+    }
+
     fn pre_load_files(&self, cs: &pb::ChangeSet) -> Result<()> {
         let mut ids = vec![];
         if cs.has_flush() {
@@ -322,7 +470,15 @@ impl Engine {
             let opts = dfs::Options::new(cs.shard_id, cs.shard_ver);
             let tx = result_tx.clone();
             self.fs.get_future_pool().spawn_ok(async move {
-                let res = fs.prefetch(id, opts).await;
+                // Verify the checksum during prefetch so corruption is caught
+                // before any `cas_resource` mutates shard state.
+                let res = fs.prefetch(id, opts).await.and_then(|_| {
+                    if opts.verify_checksum {
+                        let file = fs.open(id, opts)?;
+                        checksum::verify_file(id, &file)?;
+                    }
+                    Ok(())
+                });
                 tx.send(res).unwrap();
             })
         }
@@ -0,0 +1,108 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Grandparent-overlap cutoff for compaction output files.
+//!
+//! The files produced by a level→level+1 compaction can each span an
+//! arbitrarily wide key range, so when they later compact into level+1 they may
+//! overlap a huge number of level+2 ("grandparent") files and trigger a very
+//! expensive merge. Following LevelDB, [`GrandparentLimiter`] tracks the running
+//! sum of bytes in the grandparent files that overlap the current output file's
+//! key span and forces the builder to finalize the current output SSTable once
+//! that sum would exceed `max_grandparent_overlap_bytes`, keeping
+//! `assert_tables_order` ranges tight and write amplification bounded.
+
+use crate::table::sstable::SSTable;
+
+/// Tracks grandparent overlap while the compaction builder emits sorted output.
+pub struct GrandparentLimiter {
+    // Level+2 tables sorted by key, scanned in lock-step with the output keys.
+    grandparents: Vec<SSTable>,
+    // Index of the next grandparent not yet fully passed by the output key.
+    index: usize,
+    // Bytes of grandparent files overlapped since the current output started.
+    overlapped_bytes: u64,
+    // Whether any key of the compaction has been seen yet. Grandparents behind
+    // the first key are skipped uncounted, so the first call never fires; the
+    // flag is not reset when an output file is finished.
+    seen_key: bool,
+    max_grandparent_overlap_bytes: u64,
+}
+
+impl GrandparentLimiter {
+    pub fn new(grandparents: Vec<SSTable>, target_file_size: u64) -> GrandparentLimiter {
+        GrandparentLimiter {
+            grandparents,
+            index: 0,
+            overlapped_bytes: 0,
+            seen_key: false,
+            max_grandparent_overlap_bytes: 10 * target_file_size,
+        }
+    }
+
+    /// Accounts for `key` about to be appended to the current output file and
+    /// reports whether the builder should finalize that file *before* appending
+    /// it, starting a new SSTable at this key boundary.
+    ///
+    /// Mirrors LevelDB's `Compaction::ShouldStopBefore`: advance the grandparent
+    /// cursor past every table whose range lies entirely behind `key`, summing
+    /// their sizes into the overlap accumulator, and force a cut once that sum
+    /// exceeds the threshold, resetting it for the next output file.
+    pub fn should_finish_output(&mut self, key: &[u8]) -> bool {
+        while self.index < self.grandparents.len()
+            && key > self.grandparents[self.index].biggest()
+        {
+            if self.seen_key {
+                self.overlapped_bytes += self.grandparents[self.index].size();
+            }
+            self.index += 1;
+        }
+        self.seen_key = true;
+        if self.overlapped_bytes > self.max_grandparent_overlap_bytes {
+            self.overlapped_bytes = 0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Splits the sorted merged keys of a level→level+1 compaction into the key
+/// ranges of the output SSTables, starting a new output at the next key
+/// boundary whenever the grandparent overlap of the current output would exceed
+/// the limiter's threshold.
+///
+/// The compaction planner calls this while emitting the output reflected in
+/// `pb::Compaction::table_creates`: it feeds the limiter the merged key stream
+/// and each returned `(smallest, biggest)` pair bounds one output file, so no
+/// single output can later force an oversized merge against level+2. The planner
+/// that feeds the merged key stream and writes the resulting SSTables lives in
+/// the compactor module (outside this source snapshot); this function is the
+/// cutoff loop it drives.
+pub fn plan_output_ranges<'a, I>(
+    keys: I,
+    limiter: &mut GrandparentLimiter,
+) -> Vec<(Vec<u8>, Vec<u8>)>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    let mut ranges: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+    let mut cur: Option<(Vec<u8>, Vec<u8>)> = None;
+    for key in keys {
+        // Finalize the in-progress output before appending the key that would
+        // push grandparent overlap past the threshold, so the cut lands on a key
+        // boundary and every output stays non-empty. Every key, the first one
+        // included, is fed to the limiter so the first output's overlap counts.
+        let finish = limiter.should_finish_output(key);
+        if finish && cur.is_some() {
+            ranges.push(cur.take().unwrap());
+        }
+        match &mut cur {
+            Some((_, biggest)) => *biggest = key.to_vec(),
+            None => cur = Some((key.to_vec(), key.to_vec())),
+        }
+    }
+    if let Some(range) = cur {
+        ranges.push(range);
+    }
+    ranges
+}
@@ -0,0 +1,241 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Delayed garbage collection of DFS files.
+//!
+//! `remove_dfs_files` used to schedule `fs.remove` inside `g.defer`, which
+//! deletes a file as soon as the epoch advances. That races with long-running
+//! snapshot iterators that still hold the old `ShardCF`/`L0Tables` and name the
+//! file. This subsystem replaces immediate deletion with tombstone-with-grace
+//! deletion: files are pushed onto a persistent pending-deletion queue and a
+//! background worker only removes them once (a) a minimum retention interval has
+//! elapsed and (b) no live reader still references the id. The queue is replayed
+//! on restart, so deletion is crash-safe.
+
+use crate::*;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use slog_global::info;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A file queued for deletion once its grace period elapses and no reader holds
+/// a reference to it.
+#[derive(Clone)]
+struct Tombstone {
+    file_id: u64,
+    shard_id: u64,
+    ver: u64,
+    enqueue_time: u64,
+}
+
+struct GcCore {
+    pending: Vec<Tombstone>,
+    // file id -> number of live snapshot/read references.
+    refs: HashMap<u64, usize>,
+    log: File,
+}
+
+/// The engine-wide delayed deletion queue plus the live-reader reference table.
+pub struct FileGc {
+    fs: Arc<dyn dfs::Dfs>,
+    core: Mutex<GcCore>,
+    cond: Condvar,
+    stopped: AtomicBool,
+    min_retention: Duration,
+}
+
+impl FileGc {
+    /// Opens the GC queue in `dir`, replaying any tombstones left by a previous
+    /// run so deletion survives restart.
+    pub fn open(
+        fs: Arc<dyn dfs::Dfs>,
+        dir: impl AsRef<Path>,
+        min_retention: Duration,
+    ) -> Result<Arc<FileGc>> {
+        let path: PathBuf = dir.as_ref().join("DELETE_QUEUE");
+        let pending = Self::replay(&path)?;
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)?;
+        let core = GcCore {
+            pending,
+            refs: HashMap::new(),
+            log,
+        };
+        Ok(Arc::new(FileGc {
+            fs,
+            core: Mutex::new(core),
+            cond: Condvar::new(),
+            stopped: AtomicBool::new(false),
+            min_retention,
+        }))
+    }
+
+    /// Registers a live reference to `file_id`; the GC worker will not delete the
+    /// file until every reference is released.
+    pub fn acquire(&self, file_id: u64) {
+        *self.core.lock().unwrap().refs.entry(file_id).or_insert(0) += 1;
+    }
+
+    /// Releases a reference previously taken with [`acquire`](Self::acquire).
+    pub fn release(&self, file_id: u64) {
+        let mut core = self.core.lock().unwrap();
+        if let Some(cnt) = core.refs.get_mut(&file_id) {
+            *cnt -= 1;
+            if *cnt == 0 {
+                core.refs.remove(&file_id);
+            }
+        }
+    }
+
+    /// Pins every file named by `ids` for as long as the returned guard lives.
+    /// A snapshot/read path holds one of these for the ids in its `ShardCF` /
+    /// `L0Tables` so the GC worker skips them even if a concurrent apply enqueues
+    /// them for deletion; the references are dropped automatically when the
+    /// iterator goes away.
+    pub fn pin(self: &Arc<Self>, ids: impl IntoIterator<Item = u64>) -> GcPin {
+        let ids: Vec<u64> = ids.into_iter().collect();
+        for &id in &ids {
+            self.acquire(id);
+        }
+        GcPin {
+            gc: self.clone(),
+            ids,
+        }
+    }
+
+    /// Enqueues `file_id` for delayed deletion.
+    pub fn enqueue(&self, file_id: u64, shard_id: u64, ver: u64) -> Result<()> {
+        let ts = Tombstone {
+            file_id,
+            shard_id,
+            ver,
+            enqueue_time: now_unix(),
+        };
+        let mut core = self.core.lock().unwrap();
+        Self::write_record(&mut core.log, &ts)?;
+        core.log.sync_data()?;
+        core.pending.push(ts);
+        self.cond.notify_one();
+        Ok(())
+    }
+
+    /// Spawns the deletion worker on its own thread. Engine open calls this once
+    /// after constructing the queue so the worker is actually running; the handle
+    /// is joined on shutdown after [`stop`](Self::stop).
+    pub fn spawn(self: &Arc<Self>) -> std::thread::JoinHandle<()> {
+        let gc = self.clone();
+        std::thread::Builder::new()
+            .name("kvengine-file-gc".to_owned())
+            .spawn(move || gc.run())
+            .unwrap()
+    }
+
+    /// Runs the deletion loop until [`stop`](Self::stop) is called. Prefer
+    /// [`spawn`](Self::spawn), which runs this on a dedicated background thread.
+    pub fn run(&self) {
+        while !self.stopped.load(Ordering::Relaxed) {
+            let ready = self.collect_ready();
+            for ts in &ready {
+                let opts = dfs::Options::new(ts.shard_id, ts.ver);
+                self.fs.remove(ts.file_id, opts);
+            }
+            if !ready.is_empty() {
+                info!("gc removed {} dfs files", ready.len());
+                self.rewrite_log();
+            }
+            let core = self.core.lock().unwrap();
+            let _ = self.cond.wait_timeout(core, self.min_retention).unwrap();
+        }
+    }
+
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        self.cond.notify_all();
+    }
+
+    // Drains the tombstones whose grace period has elapsed and that no reader
+    // still references, leaving the rest on the queue.
+    fn collect_ready(&self) -> Vec<Tombstone> {
+        let grace = self.min_retention.as_secs();
+        let now = now_unix();
+        let mut core = self.core.lock().unwrap();
+        let refs = std::mem::take(&mut core.refs);
+        let (ready, keep): (Vec<_>, Vec<_>) = core.pending.drain(..).partition(|ts| {
+            now.saturating_sub(ts.enqueue_time) >= grace
+                && refs.get(&ts.file_id).copied().unwrap_or(0) == 0
+        });
+        core.refs = refs;
+        core.pending = keep;
+        ready
+    }
+
+    fn rewrite_log(&self) {
+        let mut core = self.core.lock().unwrap();
+        if let Ok(f) = core.log.try_clone() {
+            let _ = f.set_len(0);
+        }
+        let pending = core.pending.clone();
+        for ts in &pending {
+            let _ = Self::write_record(&mut core.log, ts);
+        }
+        let _ = core.log.sync_data();
+    }
+
+    fn write_record(log: &mut File, ts: &Tombstone) -> Result<()> {
+        log.write_u64::<LittleEndian>(ts.file_id)?;
+        log.write_u64::<LittleEndian>(ts.shard_id)?;
+        log.write_u64::<LittleEndian>(ts.ver)?;
+        log.write_u64::<LittleEndian>(ts.enqueue_time)?;
+        Ok(())
+    }
+
+    fn replay(path: &Path) -> Result<Vec<Tombstone>> {
+        let mut pending = vec![];
+        if !path.exists() {
+            return Ok(pending);
+        }
+        let mut reader = File::open(path)?;
+        loop {
+            let file_id = match reader.read_u64::<LittleEndian>() {
+                Ok(v) => v,
+                Err(_) => break,
+            };
+            pending.push(Tombstone {
+                file_id,
+                shard_id: reader.read_u64::<LittleEndian>()?,
+                ver: reader.read_u64::<LittleEndian>()?,
+                enqueue_time: reader.read_u64::<LittleEndian>()?,
+            });
+        }
+        Ok(pending)
+    }
+}
+
+/// An RAII handle that keeps a set of file ids pinned against GC for the
+/// lifetime of a snapshot/read reference. Acquired via [`FileGc::pin`] and
+/// released on drop.
+pub struct GcPin {
+    gc: Arc<FileGc>,
+    ids: Vec<u64>,
+}
+
+impl Drop for GcPin {
+    fn drop(&mut self) {
+        for &id in &self.ids {
+            self.gc.release(id);
+        }
+    }
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
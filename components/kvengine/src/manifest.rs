@@ -0,0 +1,316 @@
+// Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
+
+use crate::*;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use kvenginepb as pb;
+use protobuf::Message;
+use slog_global::info;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const MANIFEST_FILE_PREFIX: &str = "MANIFEST-";
+const CURRENT_FILE: &str = "CURRENT";
+// Rewrite the log into a fresh snapshot edit once it grows past this many edits.
+const MAX_MANIFEST_EDITS: usize = 16 * 1024;
+
+/// The live layout of a single shard as replayed from the manifest: the set of
+/// live file ids together with their cf, level and key range. This is enough to
+/// reopen the shard's `L0Tables` / `ShardCF.levels` from DFS without replaying
+/// the flush/compaction history that produced them.
+#[derive(Default, Clone)]
+pub struct ShardManifest {
+    pub ver: u64,
+    pub seq: u64,
+    pub split_stage: i32,
+    // file id -> (cf, level, smallest, biggest); cf < 0 marks an L0 table.
+    pub files: HashMap<u64, FileMeta>,
+}
+
+#[derive(Clone)]
+pub struct FileMeta {
+    pub cf: i32,
+    pub level: u32,
+    pub smallest: Vec<u8>,
+    pub biggest: Vec<u8>,
+}
+
+/// A LevelDB-style append-only manifest for a single engine. Every successfully
+/// applied `pb::ChangeSet` is serialized as an edit record and fsynced before
+/// the in-memory resources are swapped, so a crash can never leave the engine
+/// with applied-but-unrecorded state. The live-file set reconstructed by
+/// [`Manifest::replay`] is exactly the set that `remove_dfs_files` has not yet
+/// deleted.
+pub struct Manifest {
+    dir: PathBuf,
+    core: Mutex<ManifestCore>,
+}
+
+struct ManifestCore {
+    file: File,
+    num: u64,
+    edits: usize,
+    shards: HashMap<u64, ShardManifest>,
+}
+
+impl Manifest {
+    /// Opens the manifest in `dir`, replaying the active log named by `CURRENT`
+    /// (creating a fresh one if none exists) so the returned handle already
+    /// reflects the persisted layout of every shard.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Manifest> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let current = dir.join(CURRENT_FILE);
+        let (num, shards) = if current.exists() {
+            let mut name = String::new();
+            File::open(&current)?.read_to_string(&mut name)?;
+            let num = name
+                .trim()
+                .strip_prefix(MANIFEST_FILE_PREFIX)
+                .and_then(|s| s.parse::<u64>().ok())
+                .ok_or(Error::ManifestCorruption)?;
+            (num, Self::replay(&dir.join(name.trim()))?)
+        } else {
+            (1, HashMap::new())
+        };
+        let path = dir.join(format!("{}{}", MANIFEST_FILE_PREFIX, num));
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)?;
+        let core = ManifestCore {
+            file,
+            num,
+            edits: 0,
+            shards,
+        };
+        let m = Manifest {
+            dir,
+            core: Mutex::new(core),
+        };
+        m.write_current(num)?;
+        Ok(m)
+    }
+
+    /// Appends `cs` to the log and fsyncs it. Call this from `apply_change_set`
+    /// *before* the in-memory CAS so the record is durable first.
+    pub fn append(&self, cs: &pb::ChangeSet) -> Result<()> {
+        let mut core = self.core.lock().unwrap();
+        Self::write_edit(&mut core.file, cs)?;
+        core.file.sync_data()?;
+        apply_edit(&mut core.shards, cs);
+        core.edits += 1;
+        if core.edits >= MAX_MANIFEST_EDITS {
+            self.rewrite(&mut core)?;
+        }
+        Ok(())
+    }
+
+    /// The live file ids the manifest currently records for `shard_id`.
+    pub fn live_files(&self, shard_id: u64) -> HashSet<u64> {
+        let core = self.core.lock().unwrap();
+        core.shards
+            .get(&shard_id)
+            .map(|s| s.files.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn shard(&self, shard_id: u64) -> Option<ShardManifest> {
+        self.core.lock().unwrap().shards.get(&shard_id).cloned()
+    }
+
+    /// The Merkle root of `shard_id`'s live file set as recorded in the manifest.
+    /// Available immediately after restart (derived from the replayed live set)
+    /// so cross-replica anti-entropy can compare roots without reopening DFS.
+    pub fn shard_merkle_root(&self, shard_id: u64) -> Option<merkle::Hash> {
+        self.core
+            .lock()
+            .unwrap()
+            .shards
+            .get(&shard_id)
+            .map(|s| s.merkle_root())
+    }
+
+    fn replay(path: &Path) -> Result<HashMap<u64, ShardManifest>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut shards = HashMap::new();
+        loop {
+            let len = match reader.read_u32::<LittleEndian>() {
+                Ok(len) => len as usize,
+                Err(_) => break,
+            };
+            let crc = reader.read_u32::<LittleEndian>()?;
+            let mut buf = vec![0u8; len];
+            reader.read_exact(&mut buf)?;
+            if checksum::crc32c(&buf) != crc {
+                return Err(Error::ManifestCorruption);
+            }
+            let mut cs = pb::ChangeSet::new();
+            cs.merge_from_bytes(&buf)?;
+            apply_edit(&mut shards, &cs);
+        }
+        Ok(shards)
+    }
+
+    fn write_edit(file: &mut File, cs: &pb::ChangeSet) -> Result<()> {
+        let buf = cs.write_to_bytes()?;
+        file.write_u32::<LittleEndian>(buf.len() as u32)?;
+        file.write_u32::<LittleEndian>(checksum::crc32c(&buf))?;
+        file.write_all(&buf)?;
+        Ok(())
+    }
+
+    // Compacts the log by writing the full live file set into a fresh manifest
+    // as one snapshot edit per shard, then repointing `CURRENT` at it.
+    fn rewrite(&self, core: &mut ManifestCore) -> Result<()> {
+        let num = core.num + 1;
+        let path = self.dir.join(format!("{}{}", MANIFEST_FILE_PREFIX, num));
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)?;
+        for (shard_id, shard) in &core.shards {
+            Self::write_edit(&mut file, &shard.to_snapshot_edit(*shard_id))?;
+        }
+        file.sync_data()?;
+        let old_num = core.num;
+        core.file = file;
+        core.num = num;
+        core.edits = 0;
+        // `core` is already locked by the caller, so pass the number explicitly.
+        self.write_current(num)?;
+        let _ = fs::remove_file(self.dir.join(format!("{}{}", MANIFEST_FILE_PREFIX, old_num)));
+        info!("rewrote manifest {} -> {}", old_num, num);
+        Ok(())
+    }
+
+    fn write_current(&self, num: u64) -> Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", CURRENT_FILE));
+        let mut f = File::create(&tmp)?;
+        write!(f, "{}{}", MANIFEST_FILE_PREFIX, num)?;
+        f.sync_data()?;
+        fs::rename(&tmp, self.dir.join(CURRENT_FILE))?;
+        Ok(())
+    }
+}
+
+impl ShardManifest {
+    /// Folds the replayed live file set into the same Merkle fingerprint that
+    /// `apply.rs::update_shard_merkle` maintains, so the root a replica exposes
+    /// after restart matches the one it held before going down.
+    pub fn merkle_root(&self) -> merkle::Hash {
+        let entries = self
+            .files
+            .iter()
+            .map(|(id, meta)| merkle::FileEntry {
+                cf: meta.cf,
+                level: meta.level,
+                file_id: *id,
+                smallest: meta.smallest.clone(),
+                biggest: meta.biggest.clone(),
+            })
+            .collect();
+        merkle::MerkleTree::build(entries).root()
+    }
+
+    fn to_snapshot_edit(&self, shard_id: u64) -> pb::ChangeSet {
+        let mut cs = pb::ChangeSet::new();
+        cs.set_shard_id(shard_id);
+        cs.set_shard_ver(self.ver);
+        cs.set_sequence(self.seq);
+        cs.set_stage(pb::SplitStage::from_i32(self.split_stage).unwrap_or_default());
+        let mut snap = pb::Snapshot::new();
+        for (id, meta) in &self.files {
+            if meta.cf < 0 {
+                let mut l0 = pb::L0Create::new();
+                l0.set_id(*id);
+                l0.set_smallest(meta.smallest.clone());
+                l0.set_biggest(meta.biggest.clone());
+                snap.mut_l0_creates().push(l0);
+            } else {
+                let mut t = pb::TableCreate::new();
+                t.set_id(*id);
+                t.set_cf(meta.cf);
+                t.set_level(meta.level);
+                t.set_smallest(meta.smallest.clone());
+                t.set_biggest(meta.biggest.clone());
+                snap.mut_table_creates().push(t);
+            }
+        }
+        cs.set_snapshot(snap);
+        cs
+    }
+}
+
+// Folds a single change set edit into the in-memory shard layout. Mirrors the
+// file-set mutations performed by the matching `apply_*` method in apply.rs.
+fn apply_edit(shards: &mut HashMap<u64, ShardManifest>, cs: &pb::ChangeSet) {
+    let shard = shards.entry(cs.shard_id).or_default();
+    if cs.sequence != 0 {
+        shard.seq = cs.sequence;
+    }
+    shard.ver = cs.shard_ver;
+    shard.split_stage = cs.stage as i32;
+    if cs.has_snapshot() {
+        shard.files.clear();
+        let snap = cs.get_snapshot();
+        insert_l0s(shard, snap.get_l0_creates());
+        insert_tables(shard, snap.get_table_creates());
+    } else if cs.has_flush() {
+        let flush = cs.get_flush();
+        if flush.has_l0_create() {
+            insert_l0s(shard, std::slice::from_ref(flush.get_l0_create()));
+        }
+    } else if cs.has_compaction() {
+        let comp = cs.get_compaction();
+        if comp.conflicted {
+            return;
+        }
+        for id in comp.get_top_deletes() {
+            shard.files.remove(id);
+        }
+        for id in comp.get_bottom_deletes() {
+            shard.files.remove(id);
+        }
+        insert_tables(shard, comp.get_table_creates());
+    } else if cs.has_split_files() {
+        let sf = cs.get_split_files();
+        for id in sf.get_table_deletes() {
+            shard.files.remove(id);
+        }
+        insert_l0s(shard, sf.get_l0_creates());
+        insert_tables(shard, sf.get_table_creates());
+    }
+}
+
+fn insert_l0s(shard: &mut ShardManifest, l0s: &[pb::L0Create]) {
+    for l0 in l0s {
+        shard.files.insert(
+            l0.id,
+            FileMeta {
+                cf: -1,
+                level: 0,
+                smallest: l0.get_smallest().to_vec(),
+                biggest: l0.get_biggest().to_vec(),
+            },
+        );
+    }
+}
+
+fn insert_tables(shard: &mut ShardManifest, tables: &[pb::TableCreate]) {
+    for t in tables {
+        shard.files.insert(
+            t.id,
+            FileMeta {
+                cf: t.cf,
+                level: t.level,
+                smallest: t.get_smallest().to_vec(),
+                biggest: t.get_biggest().to_vec(),
+            },
+        );
+    }
+}